@@ -3,15 +3,59 @@ use syn::visit::Visit;
 use syn::visit_mut::VisitMut;
 
 #[derive(Default)]
-pub(crate) struct NormalizeLifetimes;
+pub(crate) struct NormalizeLifetimes {
+    /// Whether the configured MSRV supports precise-capturing `use<>` bounds on
+    /// `impl Trait` return types (stabilized in Rust 1.82). When `false` the
+    /// `use<>` normalization step is skipped, so older toolchains keep building.
+    pub(crate) supports_use_bounds: bool,
+
+    /// Generics of the enclosing `impl`/`trait` (if any). Their parameters are
+    /// also in scope for the methods inside: their type/const parameters are
+    /// always part of the precise-capturing set of a method's `impl Trait`
+    /// return type, their lifetimes only if the method's signature references them.
+    enclosing_generics: Option<syn::Generics>,
+
+    /// Whether the signature currently being visited belongs to an `impl` or
+    /// `trait`. `Self` may only be named in a `use<>` bound in that context.
+    in_impl_or_trait: bool,
+}
+
+impl NormalizeLifetimes {
+    /// Creates a normalizer. `supports_use_bounds` must be wired from the
+    /// configured MSRV at the macro entry point — precise-capturing `use<>`
+    /// bounds were stabilized in Rust 1.82, e.g.
+    /// `NormalizeLifetimes::new(msrv.map_or(false, Msrv::supports_use_bounds))`.
+    pub(crate) fn new(supports_use_bounds: bool) -> Self {
+        Self {
+            supports_use_bounds,
+            ..Default::default()
+        }
+    }
+}
 
 impl VisitMut for NormalizeLifetimes {
     fn visit_item_impl_mut(&mut self, impl_block: &mut syn::ItemImpl) {
+        let prev_generics = self.enclosing_generics.replace(impl_block.generics.clone());
+        let prev_context = std::mem::replace(&mut self.in_impl_or_trait, true);
+
         syn::visit_mut::visit_item_impl_mut(self, impl_block);
 
+        self.enclosing_generics = prev_generics;
+        self.in_impl_or_trait = prev_context;
+
         AssignLifetimes::new("i", &mut impl_block.generics).visit_type_mut(&mut impl_block.self_ty);
     }
 
+    fn visit_item_trait_mut(&mut self, trait_item: &mut syn::ItemTrait) {
+        let prev_generics = self.enclosing_generics.replace(trait_item.generics.clone());
+        let prev_context = std::mem::replace(&mut self.in_impl_or_trait, true);
+
+        syn::visit_mut::visit_item_trait_mut(self, trait_item);
+
+        self.enclosing_generics = prev_generics;
+        self.in_impl_or_trait = prev_context;
+    }
+
     fn visit_impl_item_fn_mut(&mut self, fn_item: &mut syn::ImplItemFn) {
         // We are interested only in signatures of functions. Don't recurse
         // into the function's block.
@@ -19,6 +63,31 @@ impl VisitMut for NormalizeLifetimes {
     }
 
     fn visit_signature_mut(&mut self, signature: &mut syn::Signature) {
+        // Snapshot the generic parameters that are genuinely in scope in the
+        // *original* signature, before `AssignLifetimes` injects the synthetic
+        // `'__f{n}` lifetimes below. These form the precise-capturing set of an
+        // opaque return type.
+        let precise_capturing = self.supports_use_bounds.then(|| {
+            // Every in-scope type/const parameter must be captured (an opaque
+            // type always captured those, on all editions). Lifetimes are
+            // different: on editions < 2024 an RPIT only captures the lifetimes
+            // it actually mentions, so we restrict the captured lifetimes to
+            // those genuinely referenced in the *original* (pre-injection)
+            // signature. Capturing more would over-constrain the returned type
+            // relative to the user's source.
+            let referenced = referenced_lifetimes(signature);
+
+            let mut captured = CapturedParams::default();
+            if let Some(generics) = &self.enclosing_generics {
+                captured.add(generics, &referenced);
+            }
+            captured.add(&signature.generics, &referenced);
+
+            // `Self` can only be named in a `use<>` bound inside an `impl`/`trait`;
+            // free functions must not capture it.
+            captured.into_list(self.in_impl_or_trait)
+        });
+
         let mut visitor = AssignLifetimes::new("f", &mut signature.generics);
         for arg in &mut signature.inputs {
             visitor.visit_fn_arg_mut(arg);
@@ -28,6 +97,17 @@ impl VisitMut for NormalizeLifetimes {
             return;
         };
 
+        // `impl Trait` return types implicitly capture every in-scope lifetime in
+        // Rust 2024, which now includes the synthetic `'__f{n}` parameters we
+        // just injected. Pin the capture set back to the original parameters with
+        // an explicit `+ use<..>` bound so the returned type isn't over-constrained.
+        if let Some(captured) = &precise_capturing {
+            AddPreciseCapturing {
+                captured: captured.as_slice(),
+            }
+            .visit_type_mut(return_type);
+        }
+
         // Now perform lifetime elision for the lifetimes in the return type.
         // This code implements the logic described in the Rust reference:
         // https://doc.rust-lang.org/reference/lifetime-elision.html
@@ -37,11 +117,23 @@ impl VisitMut for NormalizeLifetimes {
             .first()
             .and_then(|arg| {
                 let receiver = arg.as_receiver()?;
-                receiver.lifetime().or_else(|| {
-                    let syn::Type::Reference(reference) = receiver.ty.as_ref() else {
-                        return None;
-                    };
-                    reference.lifetime.as_ref()
+                receiver.lifetime().or_else(|| match receiver.ty.as_ref() {
+                    // `&self` / `&mut self` or a `self: &Self` receiver.
+                    syn::Type::Reference(reference) => reference.lifetime.as_ref(),
+                    // An arbitrary self type such as `self: Pin<&mut Self>` or
+                    // `self: Box<&Self>`. Recurse into the wrapper collecting the
+                    // lifetimes attached to references that contain `Self`. If
+                    // exactly one exists it is the elided output lifetime; zero or
+                    // multiple are ambiguous, so fall back to the typed-argument
+                    // path below (matching the `LifetimeCollector::Single` logic).
+                    other => {
+                        let mut collector = SelfReferenceLifetimes::None;
+                        collector.visit_type(other);
+                        match collector {
+                            SelfReferenceLifetimes::Single(lifetime) => Some(lifetime),
+                            _ => None,
+                        }
+                    }
                 })
             })
             .or_else(|| {
@@ -68,6 +160,110 @@ impl VisitMut for NormalizeLifetimes {
     }
 }
 
+/// Rewrites the signature of an `async fn` finishing method so that it returns
+/// a boxed future (`Pin<Box<dyn Future<Output = T> + Send + 'fut>>`) instead of
+/// an anonymous `impl Future`. This is the same technique `async-trait` uses to
+/// make async functions object-safe and their futures storable in collections.
+///
+/// The finisher codegen that consumes this (parsing `#[builder(boxed)]` /
+/// `boxed(?Send)` and wrapping the finishing body in `Box::pin(async move { .. })`)
+/// lives in the builder modules and calls this for the signature side.
+///
+/// `send` controls whether the `+ Send` bound is present; pass `false` for the
+/// `#[builder(boxed(?Send))]` opt-out used with non-`Send` futures.
+pub(crate) fn box_async_finisher(signature: &mut syn::Signature, send: bool) {
+    // The elided output/return lifetimes must be resolved into named parameters
+    // before we gather the input lifetimes below, otherwise the boxed future's
+    // `'lt: 'fut` bounds would be incomplete.
+    NormalizeLifetimes::default().visit_signature_mut(signature);
+
+    let output = match &signature.output {
+        syn::ReturnType::Type(_, ty) => (**ty).clone(),
+        syn::ReturnType::Default => syn::parse_quote!(()),
+    };
+
+    // Collect *every* input lifetime: the freshly-assigned `'__f{n}` elided ones,
+    // the explicit ones, and the receiver's lifetime.
+    let mut collector = CollectLifetimes::default();
+    for arg in &signature.inputs {
+        collector.visit_fn_arg(arg);
+    }
+
+    // `Self: 'fut` is required whenever the receiver is a reference (`&self`,
+    // `&mut self`, `self: &Self`), because the boxed future captures it.
+    let borrows_self = signature
+        .inputs
+        .first()
+        .and_then(syn::FnArg::as_receiver)
+        .is_some_and(|receiver| {
+            receiver.reference.is_some() || matches!(receiver.ty.as_ref(), syn::Type::Reference(_))
+        });
+
+    let fut = syn::Lifetime::new("'fut", proc_macro2::Span::call_site());
+
+    let where_clause = signature.generics.make_where_clause();
+    let mut seen = std::collections::BTreeSet::new();
+    for lifetime in &collector.lifetimes {
+        // `'static` outlives everything already and `'_` can't appear in a bound.
+        if lifetime.ident == "static" || lifetime.ident == "_" {
+            continue;
+        }
+        if seen.insert(lifetime.ident.to_string()) {
+            where_clause.predicates.push(syn::parse_quote!(#lifetime: #fut));
+        }
+    }
+    if borrows_self {
+        where_clause.predicates.push(syn::parse_quote!(Self: #fut));
+    }
+
+    let boxed: syn::Type = if send {
+        syn::parse_quote! {
+            ::core::pin::Pin<Box<dyn ::core::future::Future<Output = #output> + ::core::marker::Send + #fut>>
+        }
+    } else {
+        syn::parse_quote! {
+            ::core::pin::Pin<Box<dyn ::core::future::Future<Output = #output> + #fut>>
+        }
+    };
+
+    signature
+        .generics
+        .params
+        .insert(0, syn::GenericParam::Lifetime(syn::LifetimeParam::new(fut)));
+    signature.asyncness = None;
+    signature.output = syn::ReturnType::Type(Default::default(), Box::new(boxed));
+}
+
+/// Gathers every lifetime that occurs in the visited nodes. Unlike
+/// [`LifetimeCollector`] it keeps all of them instead of collapsing to a single
+/// one, which is what the boxed-future bounds need.
+#[derive(Default)]
+struct CollectLifetimes {
+    lifetimes: Vec<syn::Lifetime>,
+}
+
+impl Visit<'_> for CollectLifetimes {
+    fn visit_item(&mut self, _item: &syn::Item) {
+        // Don't recurse into nested items because lifetimes aren't available there.
+    }
+
+    fn visit_type_bare_fn(&mut self, _bare_fn: &syn::TypeBareFn) {
+        // Skip function pointers because anon lifetimes that appear in them
+        // don't belong to the surrounding function signature.
+    }
+
+    fn visit_parenthesized_generic_arguments(
+        &mut self,
+        _args: &syn::ParenthesizedGenericArguments,
+    ) {
+        // Skip Fn traits for the same reason as function pointers described higher.
+    }
+
+    fn visit_lifetime(&mut self, lifetime: &syn::Lifetime) {
+        self.lifetimes.push(lifetime.clone());
+    }
+}
+
 struct AssignLifetimes<'a> {
     prefix: &'static str,
     generics: &'a mut syn::Generics,
@@ -193,6 +389,196 @@ impl<'a> Visit<'a> for LifetimeCollector<'a> {
     }
 }
 
+/// Collects the lifetimes of references that wrap `Self` within an arbitrary
+/// self type (e.g. the `'a` in `self: Pin<&'a mut Self>`). Mirrors
+/// [`LifetimeCollector`]: it reports a single such lifetime, or signals that the
+/// choice is ambiguous (`None`/`Multiple`) so the caller can fall back.
+enum SelfReferenceLifetimes<'a> {
+    None,
+    Single(&'a syn::Lifetime),
+    Multiple,
+}
+
+impl<'a> Visit<'a> for SelfReferenceLifetimes<'a> {
+    fn visit_item(&mut self, _item: &syn::Item) {
+        // Don't recurse into nested items because lifetimes aren't available there.
+    }
+
+    fn visit_type_bare_fn(&mut self, _bare_fn: &syn::TypeBareFn) {
+        // Skip function pointers because anon lifetimes that appear in them
+        // don't belong to the surrounding function signature.
+    }
+
+    fn visit_parenthesized_generic_arguments(
+        &mut self,
+        _args: &syn::ParenthesizedGenericArguments,
+    ) {
+        // Skip Fn traits for the same reason as function pointers described higher.
+    }
+
+    fn visit_type_reference(&mut self, reference: &'a syn::TypeReference) {
+        if type_contains_self(&reference.elem) {
+            if let Some(lifetime) = &reference.lifetime {
+                match self {
+                    Self::None => *self = Self::Single(lifetime),
+                    Self::Single(_) => *self = Self::Multiple,
+                    Self::Multiple => {}
+                }
+            }
+        }
+
+        syn::visit::visit_type_reference(self, reference);
+    }
+}
+
+/// Returns `true` if `ty` mentions the `Self` type anywhere.
+fn type_contains_self(ty: &syn::Type) -> bool {
+    struct FindSelf(bool);
+
+    impl Visit<'_> for FindSelf {
+        fn visit_path(&mut self, path: &syn::Path) {
+            if path.is_ident("Self") {
+                self.0 = true;
+            }
+            syn::visit::visit_path(self, path);
+        }
+    }
+
+    let mut finder = FindSelf(false);
+    finder.visit_type(ty);
+    finder.0
+}
+
+/// Appends a precise-capturing `+ use<..>` bound to every outermost `impl Trait`
+/// found in return position, unless the user already wrote one. An `impl Trait`
+/// nested in another one's bounds gets no bound. `captured` is the list of
+/// parameters from the original signature, before the synthetic lifetimes were
+/// injected: all type/const parameters, `Self` inside an `impl`/`trait`, and the
+/// lifetimes that the signature references.
+struct AddPreciseCapturing<'a> {
+    captured: &'a [proc_macro2::TokenStream],
+}
+
+impl VisitMut for AddPreciseCapturing<'_> {
+    fn visit_item_mut(&mut self, _item: &mut syn::Item) {
+        // Don't recurse into nested items because lifetimes aren't available there.
+    }
+
+    fn visit_type_bare_fn_mut(&mut self, _bare_fn: &mut syn::TypeBareFn) {
+        // Skip function pointers because anon lifetimes that appear in them
+        // don't belong to the surrounding function signature.
+    }
+
+    fn visit_parenthesized_generic_arguments_mut(
+        &mut self,
+        _args: &mut syn::ParenthesizedGenericArguments,
+    ) {
+        // Skip Fn traits for the same reason as function pointers described higher.
+    }
+
+    fn visit_type_impl_trait_mut(&mut self, impl_trait: &mut syn::TypeImplTrait) {
+        // `use<>` is only valid on the outermost return-position `impl Trait`.
+        // We intentionally do *not* recurse into this opaque's own bounds, so a
+        // nested opaque such as the inner one in `-> impl Iterator<Item = impl
+        // Debug>` is left alone. Sibling opaques reached through other types
+        // (e.g. each element of `-> (impl A, impl B)`) are still handled because
+        // the default traversal recurses through those non-opaque types.
+
+        // Leave any user-written `use<>` bound untouched.
+        let has_use_bound = impl_trait
+            .bounds
+            .iter()
+            .any(|bound| matches!(bound, syn::TypeParamBound::PreciseCapture(_)));
+        if has_use_bound {
+            return;
+        }
+
+        let captured = self.captured;
+        impl_trait
+            .bounds
+            .push(syn::parse_quote!(use<#(#captured),*>));
+    }
+}
+
+/// Accumulates the generic parameters that a precise-capturing `use<>` bound
+/// should list, keeping lifetimes and type/const parameters apart so the final
+/// list can be emitted lifetimes-first (as the grammar requires).
+#[derive(Default)]
+struct CapturedParams {
+    lifetimes: Vec<proc_macro2::TokenStream>,
+    types: Vec<proc_macro2::TokenStream>,
+}
+
+impl CapturedParams {
+    /// Adds `generics`' parameters to the capture set. All type/const parameters
+    /// are captured; a lifetime parameter is captured only if its name appears
+    /// in `referenced_lifetimes` (see the explanation in `visit_signature_mut`).
+    fn add(
+        &mut self,
+        generics: &syn::Generics,
+        referenced_lifetimes: &std::collections::HashSet<String>,
+    ) {
+        for param in &generics.params {
+            match param {
+                syn::GenericParam::Lifetime(param) => {
+                    if referenced_lifetimes.contains(&param.lifetime.ident.to_string()) {
+                        self.lifetimes.push(param.lifetime.to_token_stream());
+                    }
+                }
+                syn::GenericParam::Type(param) => self.types.push(param.ident.to_token_stream()),
+                syn::GenericParam::Const(param) => self.types.push(param.ident.to_token_stream()),
+            }
+        }
+    }
+
+    fn into_list(mut self, include_self: bool) -> Vec<proc_macro2::TokenStream> {
+        if include_self {
+            self.types.push(quote!(Self));
+        }
+        self.lifetimes.into_iter().chain(self.types).collect()
+    }
+}
+
+/// Collects the names of the lifetimes that are actually referenced in the
+/// function's original signature (its inputs and return type), ignoring the
+/// anonymous `'_` and `'static`. Used to keep precise-capturing `use<>` bounds
+/// from capturing lifetimes the user never mentioned.
+fn referenced_lifetimes(signature: &syn::Signature) -> std::collections::HashSet<String> {
+    #[derive(Default)]
+    struct Collector(std::collections::HashSet<String>);
+
+    impl Visit<'_> for Collector {
+        fn visit_item(&mut self, _item: &syn::Item) {
+            // Don't recurse into nested items because lifetimes aren't available there.
+        }
+
+        fn visit_type_bare_fn(&mut self, _bare_fn: &syn::TypeBareFn) {
+            // Skip function pointers because anon lifetimes that appear in them
+            // don't belong to the surrounding function signature.
+        }
+
+        fn visit_parenthesized_generic_arguments(
+            &mut self,
+            _args: &syn::ParenthesizedGenericArguments,
+        ) {
+            // Skip Fn traits for the same reason as function pointers described higher.
+        }
+
+        fn visit_lifetime(&mut self, lifetime: &syn::Lifetime) {
+            if lifetime.ident != "_" && lifetime.ident != "static" {
+                self.0.insert(lifetime.ident.to_string());
+            }
+        }
+    }
+
+    let mut collector = Collector::default();
+    for arg in &signature.inputs {
+        collector.visit_fn_arg(arg);
+    }
+    collector.visit_return_type(&signature.output);
+    collector.0
+}
+
 struct ElideOutputLifetime<'a> {
     elided_lifetime: &'a syn::Lifetime,
 }
@@ -228,3 +614,146 @@ impl VisitMut for ElideOutputLifetime<'_> {
             .get_or_insert_with(|| self.elided_lifetime.clone());
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn normalize_fn(src: &str, supports_use_bounds: bool) -> syn::Signature {
+        let mut item: syn::ItemFn = syn::parse_str(src).unwrap();
+        let mut normalize = NormalizeLifetimes::new(supports_use_bounds);
+        syn::visit_mut::visit_item_fn_mut(&mut normalize, &mut item);
+        item.sig
+    }
+
+    fn normalize_first_method(src: &str, supports_use_bounds: bool) -> syn::Signature {
+        let mut item: syn::ItemImpl = syn::parse_str(src).unwrap();
+        let mut normalize = NormalizeLifetimes::new(supports_use_bounds);
+        normalize.visit_item_impl_mut(&mut item);
+        match item.items.into_iter().next().unwrap() {
+            syn::ImplItem::Fn(fn_item) => fn_item.sig,
+            _ => panic!("expected a method"),
+        }
+    }
+
+    fn output(signature: &syn::Signature) -> String {
+        match &signature.output {
+            syn::ReturnType::Type(_, ty) => ty.to_token_stream().to_string().replace(' ', ""),
+            syn::ReturnType::Default => String::new(),
+        }
+    }
+
+    fn boxed(src: &str, send: bool) -> String {
+        let mut sig: syn::Signature = syn::parse_str(src).unwrap();
+        box_async_finisher(&mut sig, send);
+        sig.to_token_stream().to_string().replace(' ', "")
+    }
+
+    #[test]
+    fn boxes_async_finisher_future_with_send() {
+        let out = boxed("async fn finish(&self, _x: &str) -> u32", true);
+
+        assert!(out.contains("fnfinish"), "{out}");
+        // The `async` sugar is replaced by an explicit boxed future.
+        assert!(!out.contains("asyncfn"), "{out}");
+        assert!(
+            out.contains(
+                "->::core::pin::Pin<Box<dyn::core::future::Future<Output=u32>\
+                 +::core::marker::Send+'fut>>"
+            ),
+            "{out}"
+        );
+        // Every input lifetime, plus `Self`, must outlive the boxed future.
+        assert!(out.contains("'__f0:'fut"), "{out}");
+        assert!(out.contains("'__f1:'fut"), "{out}");
+        assert!(out.contains("Self:'fut"), "{out}");
+    }
+
+    #[test]
+    fn boxes_async_finisher_future_without_send() {
+        let out = boxed("async fn finish(self) -> u32", false);
+
+        assert!(
+            out.contains("->::core::pin::Pin<Box<dyn::core::future::Future<Output=u32>+'fut>>"),
+            "{out}"
+        );
+        assert!(!out.contains("Send"), "{out}");
+    }
+
+    #[test]
+    fn elides_output_lifetime_through_arbitrary_self_type() {
+        let sig = normalize_first_method(
+            "impl S { fn f(self: std::pin::Pin<&mut Self>) -> &u8 { todo!() } }",
+            false,
+        );
+
+        // The lifetime assigned to the inner `&mut Self` must flow to the output.
+        assert_eq!(output(&sig), "&'__f0u8");
+    }
+
+    #[test]
+    fn precise_capturing_includes_enclosing_type_params_and_self() {
+        let sig = normalize_first_method(
+            "impl<'x, U> Foo<'x, U> { fn f<T>(&self, _x: T) -> impl Clone { todo!() } }",
+            true,
+        );
+
+        let out = output(&sig);
+        // `'x` is never referenced in the signature, so it is not captured (this
+        // preserves pre-2024 RPIT semantics); type params and `Self` always are.
+        assert!(out.contains("use<U,T,Self>"), "{out}");
+        assert!(!out.contains("'x"), "{out}");
+        // The synthetic receiver lifetime must not leak into the capture list.
+        assert!(!out.contains("'__f"), "{out}");
+    }
+
+    #[test]
+    fn precise_capturing_includes_referenced_enclosing_lifetime() {
+        let sig = normalize_first_method(
+            "impl<'x, U> Foo<'x, U> { fn f(&self, _x: &'x U) -> impl Clone { todo!() } }",
+            true,
+        );
+
+        let out = output(&sig);
+        // `'x` is referenced by an argument, so it is part of the capture set.
+        assert!(out.contains("use<'x,U,Self>"), "{out}");
+        assert!(!out.contains("'__f"), "{out}");
+    }
+
+    #[test]
+    fn precise_capturing_omits_self_for_free_functions() {
+        let sig = normalize_fn("fn f<T>(_x: T) -> impl Clone { todo!() }", true);
+
+        let out = output(&sig);
+        assert!(out.contains("use<T>"), "{out}");
+        assert!(!out.contains("Self"), "{out}");
+    }
+
+    #[test]
+    fn precise_capturing_only_on_top_level_opaque() {
+        let sig = normalize_fn(
+            "fn f() -> impl Iterator<Item = impl Clone> { std::iter::empty() }",
+            true,
+        );
+
+        let out = output(&sig);
+        // Only the outermost opaque gets a `use<>` bound, never the nested one.
+        assert_eq!(out.matches("use<").count(), 1, "{out}");
+    }
+
+    #[test]
+    fn precise_capturing_gated_behind_msrv() {
+        let sig = normalize_fn("fn f<T>(_x: T) -> impl Clone { todo!() }", false);
+
+        assert!(!output(&sig).contains("use<"));
+    }
+
+    #[test]
+    fn precise_capturing_keeps_user_written_use_bound() {
+        let sig = normalize_fn("fn f<T>(_x: T) -> impl Clone + use<> { todo!() }", true);
+
+        let out = output(&sig);
+        assert!(out.contains("use<>"), "{out}");
+        assert!(!out.contains("use<T>"), "{out}");
+    }
+}